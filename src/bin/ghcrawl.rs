@@ -1,13 +1,14 @@
 use futures::{pin_mut, stream::StreamExt};
 use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::{
-    fs::File,
-    path::{Path, PathBuf},
-};
+use std::sync::Arc;
+use std::{fs::File, path::PathBuf};
 
 use clap::Parser;
+use tokio::sync::Semaphore;
+use tracing::warn;
 
+use ghcrawl::graphql;
+use ghcrawl::store::{self, Store};
 use ghcrawl::*;
 
 #[derive(Parser, Debug)]
@@ -20,12 +21,39 @@ struct Args {
     #[arg(short, long)]
     input: Option<PathBuf>,
 
+    #[arg(long)]
+    cache_file: Option<PathBuf>,
+
+    /// Number of repositories to crawl in parallel.
+    #[arg(long, default_value_t = 1)]
+    concurrency: usize,
+
+    /// Where to record results: `file` (write to `--output`, or stdout if
+    /// unset), `sqlite:PATH`, or `postgres:CONNINFO`.
+    #[arg(long, default_value = "file")]
+    store: String,
+
+    /// Which API to crawl repositories with: `rest` (one request per
+    /// repository for languages) or `graphql` (languages batched into the
+    /// same request as the repository page).
+    #[arg(long, default_value = "rest")]
+    api: String,
+
+    /// With `--input`, also download each matched file's content into
+    /// `DOWNLOAD_DIR/<repo>/<path>` instead of only recording occurrences.
+    #[arg(long)]
+    download_dir: Option<PathBuf>,
+
     token_file: PathBuf,
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
+    assert!(
+        args.concurrency > 0,
+        "--concurrency must be at least 1, got 0"
+    );
 
     if let Some(log_file) = args.log_file {
         let log_file = File::create(log_file).unwrap();
@@ -36,8 +64,29 @@ async fn main() {
             .init();
     }
 
-    let token = std::fs::read_to_string(args.token_file).unwrap();
-    let api = github_api::GithubApi::new(token.trim().to_string());
+    let tokens_file = std::fs::read_to_string(args.token_file).unwrap();
+    let mut tokens = tokens_file.lines().filter(|l| !l.trim().is_empty());
+    let first = tokens
+        .next()
+        .expect("token_file must contain at least one token");
+    let mut builder =
+        github_api::GithubApi::builder(github_api::Credentials::Token(first.trim().to_string()));
+    for token in tokens {
+        builder = builder.add_credentials(github_api::Credentials::Token(token.trim().to_string()));
+    }
+    if let Some(cache_file) = args.cache_file {
+        builder = builder.cache_path(cache_file);
+    }
+    let api = builder.build();
+
+    let store: Arc<dyn Store> = if args.store == "file" {
+        match args.output.clone() {
+            Some(path) => Arc::new(store::FileStore::new(path)),
+            None => Arc::new(store::StdoutStore),
+        }
+    } else {
+        store::store_from_spec(&args.store).await.into()
+    };
 
     if let Some(input) = args.input {
         let s = std::fs::read_to_string(input).unwrap();
@@ -52,73 +101,115 @@ async fn main() {
             })
             .collect();
         repos.sort_by_key(|(_, _, bytes)| *bytes);
-        for (name, stars, bytes) in &repos {
-            let occurrence_query = github_api::OccurrenceQuery {
-                repo: name,
-                path: None,
-                filename: None,
-                lang: "c",
-                token: "FILE",
-            };
-            let occurrences = api.get_occurrences(occurrence_query);
-            pin_mut!(occurrences);
-            let mut paths: HashMap<String, usize> = HashMap::new();
-            while let Some(occurrence) = occurrences.next().await {
-                *paths.entry(occurrence.path).or_default() += 1;
-            }
-            if !paths.is_empty() {
-                let mut paths: Vec<_> = paths.into_iter().collect();
-                paths.sort_by_key(|(_, count)| usize::MAX - *count);
-                let mut s = String::new();
-                for (p, n) in &paths {
-                    use std::fmt::Write;
-                    write!(s, "{}: {}, ", p, n).unwrap();
-                }
-                let s = format!("{} {} {}\n{}\n", name, stars, bytes, s);
-                if let Some(output) = args.output.as_ref() {
-                    append_to_file(output, s.as_str());
-                } else {
-                    print!("{}", s);
+
+        let semaphore = Arc::new(Semaphore::new(args.concurrency));
+        let download_dir = Arc::new(args.download_dir.clone());
+        futures::stream::iter(repos.iter())
+            .map(|(name, stars, bytes)| {
+                let semaphore = semaphore.clone();
+                let api = &api;
+                let store = store.clone();
+                let download_dir = download_dir.clone();
+                async move {
+                    let _permit = semaphore.acquire().await.unwrap();
+                    let occurrence_query = github_api::OccurrenceQuery {
+                        repo: name,
+                        path: None,
+                        filename: None,
+                        lang: "c",
+                        token: "FILE",
+                    };
+                    let occurrences = api.get_occurrences(occurrence_query);
+                    pin_mut!(occurrences);
+                    let mut paths: HashMap<String, usize> = HashMap::new();
+                    while let Some(occurrence) = occurrences.next().await {
+                        *paths.entry(occurrence.path).or_default() += 1;
+                    }
+                    // Matches the original --input behavior: a repo with no
+                    // occurrence matches is skipped entirely rather than
+                    // recorded with an empty occurrence list.
+                    if paths.is_empty() {
+                        return;
+                    }
+                    store.record_repository(name, *stars, *bytes).await;
+
+                    let mut paths: Vec<_> = paths.into_iter().collect();
+                    paths.sort_by_key(|(_, count)| usize::MAX - *count);
+                    for (path, count) in &paths {
+                        if let Some(download_dir) = download_dir.as_ref() {
+                            let dest = download_dir.join(name).join(path);
+                            if let Some(parent) = dest.parent() {
+                                tokio::fs::create_dir_all(parent).await.unwrap();
+                            }
+                            if let Err(e) = api.download_file_to(name, path, &dest).await {
+                                warn!("Failed to download {}/{}: {}", name, path, e);
+                            }
+                        }
+                        store.record_occurrence(name, path, *count).await;
+                    }
                 }
-            }
-        }
+            })
+            .buffer_unordered(args.concurrency)
+            .for_each(|_| async {})
+            .await;
     } else {
         let repo_query = github_api::RepositoryQuery {
             min_stars: 1000,
             max_stars: 128000,
             lang: "c",
         };
-        let repos = api.get_repositories(repo_query);
-        pin_mut!(repos);
-        while let Some(repo) = repos.next().await {
-            let github_api::Repository {
-                full_name,
-                stargazers_count,
-            } = repo;
-
-            let langs = api.get_repository_languages(&full_name).await;
-            let total_bytes = langs.values().sum::<usize>();
-            let c_bytes = langs["C"];
-            if c_bytes * 2 < total_bytes {
-                continue;
-            }
-
-            let s = format!("{} {} {}\n", full_name, stargazers_count, c_bytes);
-            if let Some(output) = args.output.as_ref() {
-                append_to_file(output, s.as_str());
-            } else {
-                print!("{}", s);
-            }
+        if args.api == "graphql" {
+            let repos = api.get_repositories_graphql(repo_query);
+            repos
+                .map(|repo| {
+                    let store = store.clone();
+                    async move {
+                        let graphql::RepositoryLanguages {
+                            repository:
+                                github_api::Repository {
+                                    full_name,
+                                    stargazers_count,
+                                },
+                            languages,
+                        } = repo;
+
+                        let total_bytes = languages.values().sum::<usize>();
+                        let c_bytes = *languages.get("C").unwrap_or(&0);
+                        if c_bytes * 2 >= total_bytes {
+                            store
+                                .record_repository(&full_name, stargazers_count, c_bytes)
+                                .await;
+                        }
+                    }
+                })
+                .buffer_unordered(args.concurrency)
+                .for_each(|_| async {})
+                .await;
+        } else {
+            let repos = api.get_repositories(repo_query);
+            repos
+                .map(|repo| {
+                    let api = &api;
+                    let store = store.clone();
+                    async move {
+                        let github_api::Repository {
+                            full_name,
+                            stargazers_count,
+                        } = repo;
+
+                        let langs = api.get_repository_languages(&full_name).await;
+                        let total_bytes = langs.values().sum::<usize>();
+                        let c_bytes = langs["C"];
+                        if c_bytes * 2 >= total_bytes {
+                            store
+                                .record_repository(&full_name, stargazers_count, c_bytes)
+                                .await;
+                        }
+                    }
+                })
+                .buffer_unordered(args.concurrency)
+                .for_each(|_| async {})
+                .await;
         }
     }
 }
-
-fn append_to_file(path: &Path, content: &str) {
-    use std::io::Write;
-    let mut file = OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(path)
-        .unwrap();
-    file.write_all(content.as_bytes()).unwrap();
-}
@@ -2,18 +2,154 @@ use futures::stream::Stream;
 use futures::stream::StreamExt;
 use std::collections::HashMap;
 use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
 use reqwest::{header, Client, Response};
 use serde::de::DeserializeOwned;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
 use tracing::info;
 
 const PER_PAGE: usize = 100;
+const DEFAULT_BASE_URL: &str = "https://api.github.com/";
+const MAX_DOWNLOAD_BACKOFF: Duration = Duration::from_secs(60);
 
-pub struct GithubApi {
+/// Credentials used to authenticate requests against the configured host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Credentials {
+    /// A classic personal access token, sent as `token {}`.
+    Token(String),
+    /// An OAuth access token, sent as `Bearer {}`.
+    Bearer(String),
+    /// A GitHub App installation access token, sent as `token {}`.
+    App { installation_token: String },
+}
+
+impl Credentials {
+    fn header_value(&self) -> String {
+        match self {
+            Credentials::Token(token) => format!("token {}", token),
+            Credentials::Bearer(token) => format!("Bearer {}", token),
+            Credentials::App { installation_token } => format!("token {}", installation_token),
+        }
+    }
+}
+
+/// Builds a [`GithubApi`], defaulting to `https://api.github.com/` unless
+/// [`GithubApiBuilder::base_url`] is used to target a GitHub Enterprise instance.
+pub struct GithubApiBuilder {
+    base_url: String,
+    credentials: Vec<Credentials>,
+    cache_path: Option<PathBuf>,
+}
+
+impl GithubApiBuilder {
+    fn new(credentials: Credentials) -> Self {
+        Self {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            credentials: vec![credentials],
+            cache_path: None,
+        }
+    }
+
+    /// Sets the API base URL, e.g. `https://github.mycompany.com/api/v3/` for
+    /// a GitHub Enterprise instance. Must end with a `/`.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    /// Adds another credential to the pool. Requests round-robin across all
+    /// pooled credentials, and a credential that hits its rate limit is
+    /// skipped until its reset time passes instead of blocking the others.
+    pub fn add_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials.push(credentials);
+        self
+    }
+
+    /// Persists the ETag cache to `path`, appending one JSON line per
+    /// updated entry, so conditional requests survive across runs without
+    /// rewriting the whole cache on every response. If `path` already
+    /// exists, it is replayed (later lines for the same URL win) at build time.
+    pub fn cache_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cache_path = Some(path.into());
+        self
+    }
+
+    pub fn build(self) -> GithubApi {
+        let clients = self.credentials.iter().map(ClientSlot::new).collect();
+        let cache = self
+            .cache_path
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map(|s| {
+                s.lines()
+                    .filter_map(|line| serde_json::from_str::<(String, CacheEntry)>(line).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let cache_file = self.cache_path.map(|path| {
+            let file = std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .unwrap();
+            Mutex::new(file)
+        });
+        GithubApi {
+            base_url: self.base_url,
+            clients,
+            next_client: Mutex::new(0),
+            cache: Mutex::new(cache),
+            cache_file,
+        }
+    }
+}
+
+/// A cached response, keyed by URL, used to make conditional `If-None-Match`
+/// requests that don't count against the rate limit when they return `304`.
+#[derive(Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    etag: String,
+    body: String,
+}
+
+/// One pooled token's HTTP client, together with the unix timestamp at which
+/// it stops being rate-limited (`0` meaning "available now").
+struct ClientSlot {
     client: Client,
+    available_at: Mutex<u64>,
+}
+
+impl ClientSlot {
+    fn new(credentials: &Credentials) -> Self {
+        let mut headers = header::HeaderMap::new();
+        let v = header::HeaderValue::from_static("ghcrawl");
+        headers.insert("User-Agent", v);
+        let v = header::HeaderValue::from_str(&credentials.header_value()).unwrap();
+        headers.insert("Authorization", v);
+        let client = Client::builder().default_headers(headers).build().unwrap();
+        Self {
+            client,
+            available_at: Mutex::new(0),
+        }
+    }
+}
+
+pub struct GithubApi {
+    base_url: String,
+    clients: Vec<ClientSlot>,
+    next_client: Mutex<usize>,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+    /// Appended to as `(url, CacheEntry)` lines rather than rewriting the
+    /// whole cache on every response, so persisting it stays O(1) per
+    /// request instead of growing with the cache size.
+    cache_file: Option<Mutex<std::fs::File>>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
@@ -62,14 +198,13 @@ enum ApiResult<T> {
 
 impl GithubApi {
     #[inline]
-    pub fn new(token: String) -> Self {
-        let mut headers = header::HeaderMap::new();
-        let v = header::HeaderValue::from_static("ghcrawl");
-        headers.insert("User-Agent", v);
-        let v = header::HeaderValue::from_str(&format!("token {}", token)).unwrap();
-        headers.insert("Authorization", v);
-        let client = Client::builder().default_headers(headers).build().unwrap();
-        Self { client }
+    pub fn new(credentials: Credentials) -> Self {
+        Self::builder(credentials).build()
+    }
+
+    #[inline]
+    pub fn builder(credentials: Credentials) -> GithubApiBuilder {
+        GithubApiBuilder::new(credentials)
     }
 
     pub async fn get_repository_languages(&self, repo: &str) -> HashMap<String, usize> {
@@ -82,6 +217,103 @@ impl GithubApi {
         self.get::<_, &str, &str>(path, &[]).await
     }
 
+    /// Streams the raw content of `repo`'s `path` into `dest`, without
+    /// holding the whole file in memory. Writes go through a `.tmp` sibling
+    /// of `dest` and are atomically renamed in on success, so a killed or
+    /// interrupted download resumes (via `Range`) from the partial `.tmp`
+    /// rather than starting over. Transient failures (connection resets,
+    /// 5xx, rate limiting) are retried with exponential backoff; a
+    /// permanent failure (404, 401, 422, ...) is returned to the caller
+    /// instead of retrying forever.
+    pub async fn download_file_to(
+        &self,
+        repo: &str,
+        path: &str,
+        dest: &Path,
+    ) -> Result<(), DownloadError> {
+        let mut url = self.base_url.clone();
+        url.push_str(&format!("repos/{}/contents/{}", repo, path));
+        let tmp = tmp_path(dest);
+
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            let resume_from = tokio::fs::metadata(&tmp)
+                .await
+                .map(|m| m.len())
+                .unwrap_or(0);
+            let client = self.next_available_client().await;
+            match self.try_download_to(client, &url, &tmp, resume_from).await {
+                Ok(()) => break,
+                Err(e) if !e.is_transient() => return Err(e),
+                Err(e) => {
+                    info!(
+                        "Download of {} failed ({}), retrying in {:?}",
+                        path, e, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_DOWNLOAD_BACKOFF);
+                }
+            }
+        }
+        tokio::fs::rename(&tmp, dest).await.unwrap();
+        Ok(())
+    }
+
+    async fn try_download_to(
+        &self,
+        client: usize,
+        url: &str,
+        tmp: &Path,
+        resume_from: u64,
+    ) -> Result<(), DownloadError> {
+        info!("GET {} (resuming from {})", url, resume_from);
+        let mut request = self.clients[client]
+            .client
+            .get(url)
+            .header(header::ACCEPT, "application/vnd.github.raw");
+        if resume_from > 0 {
+            request = request.header(header::RANGE, format!("bytes={}-", resume_from));
+        }
+        let response = request.send().await?;
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+            || response.status() == reqwest::StatusCode::FORBIDDEN
+        {
+            *self.clients[client].available_at.lock().unwrap() = get_reset(&response);
+            return Err(DownloadError::RateLimited);
+        }
+        let resuming = resume_from > 0 && response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        if !response.status().is_success() && !resuming {
+            return Err(DownloadError::Status(response.status()));
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resuming)
+            .truncate(!resuming)
+            .open(tmp)
+            .await?;
+
+        let (tx, mut rx) = mpsc::channel::<reqwest::Result<bytes::Bytes>>(16);
+        let writer = tokio::spawn(async move {
+            while let Some(chunk) = rx.recv().await {
+                file.write_all(&chunk?).await?;
+            }
+            file.flush().await?;
+            Ok::<(), DownloadError>(())
+        });
+
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            if tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+        drop(tx);
+        writer.await.unwrap()?;
+        Ok(())
+    }
+
     pub fn get_occurrences<'a>(
         &'a self,
         q: OccurrenceQuery<'a>,
@@ -116,10 +348,7 @@ impl GithubApi {
         self.get("search/code".to_string(), &params).await
     }
 
-    pub fn get_repositories(
-        &self,
-        mut q: RepositoryQuery,
-    ) -> impl Stream<Item = Repository> + '_ {
+    pub fn get_repositories(&self, mut q: RepositoryQuery) -> impl Stream<Item = Repository> + '_ {
         let min_stars = q.min_stars;
         let max_stars = q.max_stars;
         futures::stream::unfold(max_stars, move |max_stars| async move {
@@ -168,7 +397,7 @@ impl GithubApi {
         K: AsRef<str>,
         V: AsRef<str>,
     {
-        let mut url = "https://api.github.com/".to_string();
+        let mut url = self.base_url.clone();
         url.push_str(&path);
         url.push('?');
         for (i, (k, v)) in params.iter().enumerate() {
@@ -184,24 +413,124 @@ impl GithubApi {
 
     async fn get_from_url<T: DeserializeOwned>(&self, url: &str) -> T {
         loop {
-            let wait = match self.try_get_from_url(url).await {
+            let i = self.next_available_client().await;
+            match self.try_get_from_url(i, url).await {
                 ApiResult::Success(t) => break t,
-                ApiResult::RateLimit(reset) => {
-                    info!("Rate limit exceeded, waiting for {} seconds", reset);
-                    reset
+                ApiResult::RateLimit(reset_at) => {
+                    info!("Client {} rate limited until {}", i, reset_at);
+                    *self.clients[i].available_at.lock().unwrap() = reset_at;
                 }
                 ApiResult::SecondaryLimit => {
-                    info!("Secondary limit exceeded, waiting for 60 seconds");
-                    60
+                    info!("Client {} hit secondary limit, backing off 60 seconds", i);
+                    *self.clients[i].available_at.lock().unwrap() = now() + 60;
                 }
+            }
+        }
+    }
+
+    /// Picks the next client in round-robin order, skipping any still
+    /// rate-limited. If all clients are rate-limited, sleeps until the
+    /// soonest one becomes available.
+    async fn next_available_client(&self) -> usize {
+        loop {
+            let n = self.clients.len();
+            let start = {
+                let mut next = self.next_client.lock().unwrap();
+                let start = *next;
+                *next = (*next + 1) % n;
+                start
             };
+            let now = now();
+            for offset in 0..n {
+                let i = (start + offset) % n;
+                if *self.clients[i].available_at.lock().unwrap() <= now {
+                    return i;
+                }
+            }
+            let soonest = self
+                .clients
+                .iter()
+                .map(|c| *c.available_at.lock().unwrap())
+                .min()
+                .unwrap();
+            let wait = soonest.saturating_sub(now).max(1);
+            info!("All tokens rate limited, waiting for {} seconds", wait);
             tokio::time::sleep(tokio::time::Duration::from_secs(wait)).await;
         }
     }
 
-    async fn try_get_from_url<T: DeserializeOwned>(&self, url: &str) -> ApiResult<T> {
+    async fn try_get_from_url<T: DeserializeOwned>(
+        &self,
+        client: usize,
+        url: &str,
+    ) -> ApiResult<T> {
         info!("GET {}", url);
-        let response = self.client.get(url).send().await.unwrap();
+        let etag = self.cache.lock().unwrap().get(url).map(|e| e.etag.clone());
+        let mut request = self.clients[client].client.get(url);
+        if let Some(etag) = &etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        let response = request.send().await.unwrap();
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            let body = self.cache.lock().unwrap().get(url).unwrap().body.clone();
+            ApiResult::Success(serde_json::from_str(&body).unwrap())
+        } else if response.status().is_success() {
+            let etag = response
+                .headers()
+                .get(header::ETAG)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string());
+            let body = response.text().await.unwrap();
+            let value = serde_json::from_str(&body).unwrap();
+            if let Some(etag) = etag {
+                self.cache_response(url, etag, body);
+            }
+            ApiResult::Success(value)
+        } else {
+            let reset = get_reset(&response);
+            let text = response.text().await.unwrap();
+            if text.contains("secondary") {
+                ApiResult::SecondaryLimit
+            } else {
+                ApiResult::RateLimit(reset)
+            }
+        }
+    }
+
+    /// Runs a GraphQL query against `{base_url}graphql`, reusing the same
+    /// client pool and rate-limit backoff as the REST endpoints.
+    pub(crate) async fn post_graphql<T: DeserializeOwned>(&self, body: &serde_json::Value) -> T {
+        loop {
+            let i = self.next_available_client().await;
+            match self.try_post_graphql(i, body).await {
+                ApiResult::Success(t) => break t,
+                ApiResult::RateLimit(reset_at) => {
+                    info!("Client {} rate limited until {}", i, reset_at);
+                    *self.clients[i].available_at.lock().unwrap() = reset_at;
+                }
+                ApiResult::SecondaryLimit => {
+                    info!("Client {} hit secondary limit, backing off 60 seconds", i);
+                    *self.clients[i].available_at.lock().unwrap() = now() + 60;
+                }
+            }
+        }
+    }
+
+    async fn try_post_graphql<T: DeserializeOwned>(
+        &self,
+        client: usize,
+        body: &serde_json::Value,
+    ) -> ApiResult<T> {
+        let mut url = self.base_url.clone();
+        url.push_str("graphql");
+        info!("POST {}", url);
+        let response = self.clients[client]
+            .client
+            .post(url)
+            .json(body)
+            .send()
+            .await
+            .unwrap();
         if response.status().is_success() {
             ApiResult::Success(response.json().await.unwrap())
         } else {
@@ -214,6 +543,82 @@ impl GithubApi {
             }
         }
     }
+
+    fn cache_response(&self, url: &str, etag: String, body: String) {
+        let entry = CacheEntry { etag, body };
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), entry.clone());
+        if let Some(file) = &self.cache_file {
+            if let Ok(mut line) = serde_json::to_string(&(url, entry)) {
+                use std::io::Write;
+                line.push('\n');
+                let _ = file.lock().unwrap().write_all(line.as_bytes());
+            }
+        }
+    }
+}
+
+/// A failure from [`GithubApi::download_file_to`]. [`DownloadError::RateLimited`],
+/// [`DownloadError::Request`], 5xx [`DownloadError::Status`]es and [`DownloadError::Io`]
+/// errors other than permission denied, storage full or quota exceeded are
+/// transient and retried internally; the rest (4xx other than rate limiting,
+/// and those three I/O errors) are permanent and returned to the caller.
+#[derive(Debug)]
+pub enum DownloadError {
+    RateLimited,
+    Status(reqwest::StatusCode),
+    Request(reqwest::Error),
+    Io(std::io::Error),
+}
+
+impl DownloadError {
+    fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::RateLimited => true,
+            DownloadError::Status(status) => status.is_server_error(),
+            DownloadError::Request(_) => true,
+            // A disk-full or permission error on the `.tmp` file is just as
+            // permanent as a 4xx status; only surface everything else (e.g.
+            // a transient `Interrupted`) as retryable.
+            DownloadError::Io(e) => !matches!(
+                e.kind(),
+                std::io::ErrorKind::PermissionDenied
+                    | std::io::ErrorKind::StorageFull
+                    | std::io::ErrorKind::QuotaExceeded
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadError::RateLimited => write!(f, "rate limited"),
+            DownloadError::Status(status) => write!(f, "unexpected status {}", status),
+            DownloadError::Request(e) => write!(f, "request error: {}", e),
+            DownloadError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for DownloadError {
+    fn from(e: reqwest::Error) -> Self {
+        DownloadError::Request(e)
+    }
+}
+
+impl From<std::io::Error> for DownloadError {
+    fn from(e: std::io::Error) -> Self {
+        DownloadError::Io(e)
+    }
+}
+
+fn tmp_path(dest: &Path) -> PathBuf {
+    let mut name = dest.file_name().unwrap().to_os_string();
+    name.push(".tmp");
+    dest.with_file_name(name)
 }
 
 #[inline]
@@ -238,17 +643,20 @@ where
     .flatten()
 }
 
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Returns the unix timestamp at which the client used for `res` becomes
+/// available again, per the response's `X-RateLimit-Reset` header.
 fn get_reset(res: &Response) -> u64 {
     res.headers()
         .get("X-RateLimit-Reset")
         .and_then(|v| v.to_str().ok())
         .and_then(|v| v.parse::<u64>().ok())
-        .map(|v| {
-            v - SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
-        })
-        .unwrap_or(0)
+        .unwrap_or_else(now)
         + 1
 }
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures::stream::Stream;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::github_api::{GithubApi, Repository, RepositoryQuery};
+
+/// Cap on the backoff between retries of a GraphQL query whose response
+/// carried an `errors` array and no `data` because of a rate-limit error
+/// reported inside a 200 response rather than as an HTTP status.
+const MAX_GRAPHQL_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How many times to retry a body-level rate-limit error before giving up.
+/// A non-rate-limit error (malformed query, schema mismatch, ...) is
+/// permanent and is never retried.
+const MAX_GRAPHQL_RETRIES: usize = 5;
+
+/// Below this many points of remaining rate-limit budget, pace subsequent
+/// queries instead of racing through them and tripping the body-level limit.
+const LOW_RATE_LIMIT_THRESHOLD: usize = 100;
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(5);
+
+const SEARCH_REPOSITORIES_QUERY: &str = "
+query($q: String!, $cursor: String) {
+  rateLimit { cost remaining resetAt }
+  search(query: $q, type: REPOSITORY, first: 50, after: $cursor) {
+    pageInfo { hasNextPage endCursor }
+    nodes {
+      ... on Repository {
+        nameWithOwner
+        stargazerCount
+        languages(first: 20, orderBy: {field: SIZE, direction: DESC}) {
+          edges { size node { name } }
+        }
+      }
+    }
+  }
+}
+";
+
+/// A [`Repository`] together with the byte size of each language used in it,
+/// fetched in the same GraphQL round trip instead of a separate REST call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RepositoryLanguages {
+    pub repository: Repository,
+    pub languages: HashMap<String, usize>,
+}
+
+#[derive(Deserialize)]
+struct GraphQlResponse<T> {
+    data: Option<T>,
+    errors: Option<Vec<GraphQlError>>,
+}
+
+#[derive(Deserialize)]
+struct GraphQlError {
+    message: String,
+    #[serde(rename = "type")]
+    error_type: Option<String>,
+}
+
+/// Whether `errors` represents GitHub's GraphQL-level rate limiting
+/// (`"type": "RATE_LIMITED"` on a 200 response), as opposed to a permanent
+/// failure like a malformed query or schema mismatch.
+fn is_rate_limited(errors: &[GraphQlError]) -> bool {
+    errors
+        .iter()
+        .any(|e| e.error_type.as_deref() == Some("RATE_LIMITED"))
+}
+
+#[derive(Deserialize)]
+struct RateLimit {
+    cost: usize,
+    remaining: usize,
+}
+
+#[derive(Deserialize)]
+struct SearchRepositoriesData {
+    #[serde(rename = "rateLimit")]
+    rate_limit: RateLimit,
+    search: Search,
+}
+
+#[derive(Deserialize)]
+struct Search {
+    #[serde(rename = "pageInfo")]
+    page_info: PageInfo,
+    nodes: Vec<RepositoryNode>,
+}
+
+#[derive(Deserialize)]
+struct PageInfo {
+    #[serde(rename = "hasNextPage")]
+    has_next_page: bool,
+    #[serde(rename = "endCursor")]
+    end_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RepositoryNode {
+    #[serde(rename = "nameWithOwner")]
+    name_with_owner: String,
+    #[serde(rename = "stargazerCount")]
+    stargazer_count: usize,
+    languages: Option<LanguageConnection>,
+}
+
+#[derive(Deserialize)]
+struct LanguageConnection {
+    edges: Vec<LanguageEdge>,
+}
+
+#[derive(Deserialize)]
+struct LanguageEdge {
+    size: usize,
+    node: LanguageNode,
+}
+
+#[derive(Deserialize)]
+struct LanguageNode {
+    name: String,
+}
+
+impl GithubApi {
+    /// Like [`GithubApi::get_repositories`], but fetches each page of
+    /// repositories together with their language byte sizes in a single
+    /// GraphQL request instead of one REST call per repository.
+    pub fn get_repositories_graphql(
+        &self,
+        mut q: RepositoryQuery,
+    ) -> impl Stream<Item = RepositoryLanguages> + '_ {
+        let min_stars = q.min_stars;
+        let max_stars = q.max_stars;
+        futures::stream::unfold(max_stars, move |max_stars| async move {
+            q.max_stars = max_stars;
+            q.min_stars = max_stars / 2;
+            if q.min_stars < min_stars {
+                None
+            } else {
+                let repos = self.get_repositories_with_stars_graphql(q);
+                Some((repos, q.min_stars))
+            }
+        })
+        .flatten()
+    }
+
+    /// Like [`GithubApi::get_repositories_with_stars`], but fetches each page
+    /// of repositories together with their language byte sizes in a single
+    /// GraphQL request, paginating via `pageInfo`/`endCursor` instead of
+    /// `page=N`.
+    pub fn get_repositories_with_stars_graphql(
+        &self,
+        q: RepositoryQuery,
+    ) -> impl Stream<Item = RepositoryLanguages> + '_ {
+        let search_query = format!(
+            "stars:{}..{} language:{}",
+            q.min_stars,
+            q.max_stars,
+            q.lang.to_lowercase()
+        );
+        futures::stream::unfold(Some(None), move |cursor| {
+            let search_query = search_query.clone();
+            async move {
+                let cursor = cursor?;
+                let data = self.search_repositories_page(&search_query, cursor).await?;
+                let next = if data.search.page_info.has_next_page {
+                    Some(data.search.page_info.end_cursor.clone())
+                } else {
+                    None
+                };
+                let items: Vec<_> = data.search.nodes.into_iter().map(Into::into).collect();
+                Some((items, next))
+            }
+        })
+        .map(futures::stream::iter)
+        .flatten()
+    }
+
+    async fn search_repositories_page(
+        &self,
+        q: &str,
+        cursor: Option<String>,
+    ) -> Option<SearchRepositoriesData> {
+        let body = json!({
+            "query": SEARCH_REPOSITORIES_QUERY,
+            "variables": { "q": q, "cursor": cursor },
+        });
+        let mut backoff = Duration::from_secs(1);
+        let mut attempt = 0;
+        loop {
+            let response: GraphQlResponse<SearchRepositoriesData> = self.post_graphql(&body).await;
+            let Some(data) = response.data else {
+                // A 200 response with an `errors` array and no `data` is how
+                // GraphQL reports its own rate limiting; retry that with
+                // backoff instead of treating it as "no next page". Anything
+                // else (malformed query, schema mismatch, ...) is permanent,
+                // so surface it instead of spinning forever.
+                let errors = response.errors.unwrap_or_default();
+                if is_rate_limited(&errors) && attempt < MAX_GRAPHQL_RETRIES {
+                    for error in &errors {
+                        warn!("GraphQL rate limited, retrying in {:?}: {}", backoff, error.message);
+                    }
+                    attempt += 1;
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_GRAPHQL_BACKOFF);
+                    continue;
+                }
+                panic!(
+                    "GraphQL query returned no data: {}",
+                    errors
+                        .iter()
+                        .map(|e| e.message.as_str())
+                        .collect::<Vec<_>>()
+                        .join("; ")
+                );
+            };
+            info!(
+                "GraphQL query cost {}, {} remaining",
+                data.rate_limit.cost, data.rate_limit.remaining
+            );
+            if data.rate_limit.remaining < LOW_RATE_LIMIT_THRESHOLD {
+                info!(
+                    "GraphQL rate limit low ({} remaining), backing off {:?} before next page",
+                    data.rate_limit.remaining, RATE_LIMIT_BACKOFF
+                );
+                tokio::time::sleep(RATE_LIMIT_BACKOFF).await;
+            }
+            return Some(data);
+        }
+    }
+}
+
+impl From<RepositoryNode> for RepositoryLanguages {
+    fn from(node: RepositoryNode) -> Self {
+        let languages = node
+            .languages
+            .map(|c| c.edges.into_iter().map(|e| (e.node.name, e.size)).collect())
+            .unwrap_or_default();
+        RepositoryLanguages {
+            repository: Repository {
+                full_name: node.name_with_owner,
+                stargazers_count: node.stargazer_count,
+            },
+            languages,
+        }
+    }
+}
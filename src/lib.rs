@@ -0,0 +1,3 @@
+pub mod github_api;
+pub mod graphql;
+pub mod store;
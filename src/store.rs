@@ -0,0 +1,266 @@
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+/// How long a sqlite connection blocks on a lock held by another connection
+/// before giving up with `SQLITE_BUSY`, set on every connection we check out
+/// of the pool so concurrent crawl workers don't trip over each other.
+const SQLITE_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Retries of a write that still hit `SQLITE_BUSY` after `SQLITE_BUSY_TIMEOUT`
+/// elapses, before giving up.
+const SQLITE_BUSY_RETRIES: usize = 5;
+const SQLITE_BUSY_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Where crawl results are durably written. [`FileStore`] keeps the original
+/// ad-hoc text format; [`SqlStore`] gives a queryable, deduplicated sink that
+/// multiple concurrent crawl workers can write to safely.
+#[async_trait]
+pub trait Store: Send + Sync {
+    async fn record_repository(&self, full_name: &str, stars: usize, c_bytes: usize);
+    async fn record_occurrence(&self, repo: &str, path: &str, count: usize);
+}
+
+/// Prints rows to stdout. Repository rows (`name stars c_bytes`) match the
+/// format the `--input` parser expects; occurrence rows (`repo path: count`)
+/// do not parse as such and are skipped by it.
+pub struct StdoutStore;
+
+#[async_trait]
+impl Store for StdoutStore {
+    async fn record_repository(&self, full_name: &str, stars: usize, c_bytes: usize) {
+        println!("{} {} {}", full_name, stars, c_bytes);
+    }
+
+    async fn record_occurrence(&self, repo: &str, path: &str, count: usize) {
+        println!("{} {}: {}", repo, path, count);
+    }
+}
+
+/// Appends space/newline-delimited rows to a file. Repository rows
+/// (`name stars c_bytes`) match the format the `--input` parser in `ghcrawl`
+/// expects; occurrence rows (`repo path: count`) are skipped by that parser.
+///
+/// The file handle is opened once and guarded by a [`Mutex`] so that
+/// concurrent crawl workers interleave whole lines instead of racing on
+/// `O_APPEND`, which only guarantees atomicity per `write(2)` call, not
+/// across the multiple writes a naive "open, write, close" would need.
+pub struct FileStore {
+    file: Mutex<File>,
+}
+
+impl FileStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path: PathBuf = path.into();
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .unwrap();
+        Self {
+            file: Mutex::new(file),
+        }
+    }
+
+    fn append(&self, content: &str) {
+        use std::io::Write;
+        let mut file = self.file.lock().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+    }
+}
+
+#[async_trait]
+impl Store for FileStore {
+    async fn record_repository(&self, full_name: &str, stars: usize, c_bytes: usize) {
+        self.append(&format!("{} {} {}\n", full_name, stars, c_bytes));
+    }
+
+    async fn record_occurrence(&self, repo: &str, path: &str, count: usize) {
+        self.append(&format!("{} {}: {}\n", repo, path, count));
+    }
+}
+
+/// A SQL-backed [`Store`], pooled with `deadpool` so concurrent crawl workers
+/// can write through it without racing on a single file.
+pub enum SqlStore {
+    Sqlite(deadpool_sqlite::Pool),
+    Postgres(deadpool_postgres::Pool),
+}
+
+const SQLITE_SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS repositories (
+    full_name TEXT PRIMARY KEY,
+    stars INTEGER NOT NULL,
+    c_bytes INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS occurrences (
+    repo TEXT NOT NULL,
+    path TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (repo, path)
+);
+";
+
+const POSTGRES_SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS repositories (
+    full_name TEXT PRIMARY KEY,
+    stars BIGINT NOT NULL,
+    c_bytes BIGINT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS occurrences (
+    repo TEXT NOT NULL,
+    path TEXT NOT NULL,
+    count BIGINT NOT NULL,
+    PRIMARY KEY (repo, path)
+);
+";
+
+impl SqlStore {
+    /// Opens (and, if needed, creates) a SQLite database at `path`, applying
+    /// the schema migration on startup.
+    pub async fn sqlite(path: impl AsRef<Path>) -> Self {
+        let config = deadpool_sqlite::Config::new(path.as_ref().to_path_buf());
+        let pool = config
+            .create_pool(deadpool_sqlite::Runtime::Tokio1)
+            .unwrap();
+        let conn = sqlite_connection(&pool).await;
+        conn.interact(|conn| conn.execute_batch(SQLITE_SCHEMA))
+            .await
+            .unwrap()
+            .unwrap();
+        SqlStore::Sqlite(pool)
+    }
+
+    /// Connects to Postgres using `config` (a `tokio_postgres` connection
+    /// string), applying the schema migration on startup.
+    pub async fn postgres(config: &str) -> Self {
+        let pg_config: tokio_postgres::Config = config.parse().unwrap();
+        let manager = deadpool_postgres::Manager::new(pg_config, tokio_postgres::NoTls);
+        let pool = deadpool_postgres::Pool::builder(manager).build().unwrap();
+        let conn = pool.get().await.unwrap();
+        conn.batch_execute(POSTGRES_SCHEMA).await.unwrap();
+        SqlStore::Postgres(pool)
+    }
+}
+
+/// Checks a connection out of `pool`, setting [`SQLITE_BUSY_TIMEOUT`] on it so
+/// it blocks and retries internally on a lock contended by another pooled
+/// connection instead of returning `SQLITE_BUSY` immediately.
+async fn sqlite_connection(pool: &deadpool_sqlite::Pool) -> deadpool_sqlite::Connection {
+    let conn = pool.get().await.unwrap();
+    conn.interact(|conn| conn.busy_timeout(SQLITE_BUSY_TIMEOUT))
+        .await
+        .unwrap()
+        .unwrap();
+    conn
+}
+
+fn is_sqlite_busy(e: &rusqlite::Error) -> bool {
+    matches!(
+        e,
+        rusqlite::Error::SqliteFailure(ffi_err, _) if ffi_err.code == rusqlite::ErrorCode::DatabaseBusy
+    )
+}
+
+/// Runs `query` against the connection held by `conn`, retrying on
+/// `SQLITE_BUSY` (which can still occur after [`SQLITE_BUSY_TIMEOUT`] elapses
+/// under heavy write contention) up to [`SQLITE_BUSY_RETRIES`] times before
+/// giving up.
+async fn execute_with_busy_retry<F>(conn: &deadpool_sqlite::Connection, query: F)
+where
+    F: Fn(&rusqlite::Connection) -> rusqlite::Result<usize> + Send + Clone + 'static,
+{
+    for attempt in 0.. {
+        let query = query.clone();
+        let result = conn.interact(move |conn| query(conn)).await.unwrap();
+        match result {
+            Ok(_) => return,
+            Err(e) if is_sqlite_busy(&e) && attempt < SQLITE_BUSY_RETRIES => {
+                tokio::time::sleep(SQLITE_BUSY_RETRY_DELAY).await;
+            }
+            Err(e) => panic!("sqlite error: {}", e),
+        }
+    }
+}
+
+#[async_trait]
+impl Store for SqlStore {
+    async fn record_repository(&self, full_name: &str, stars: usize, c_bytes: usize) {
+        match self {
+            SqlStore::Sqlite(pool) => {
+                let conn = sqlite_connection(pool).await;
+                let full_name = full_name.to_string();
+                let stars = stars as i64;
+                let c_bytes = c_bytes as i64;
+                execute_with_busy_retry(&conn, move |conn| {
+                    conn.execute(
+                        "INSERT INTO repositories (full_name, stars, c_bytes) VALUES (?1, ?2, ?3)
+                         ON CONFLICT(full_name) DO UPDATE SET stars = excluded.stars, c_bytes = excluded.c_bytes",
+                        rusqlite::params![full_name, stars, c_bytes],
+                    )
+                })
+                .await;
+            }
+            SqlStore::Postgres(pool) => {
+                let conn = pool.get().await.unwrap();
+                conn.execute(
+                    "INSERT INTO repositories (full_name, stars, c_bytes) VALUES ($1, $2, $3)
+                     ON CONFLICT (full_name) DO UPDATE SET stars = excluded.stars, c_bytes = excluded.c_bytes",
+                    &[&full_name, &(stars as i64), &(c_bytes as i64)],
+                )
+                .await
+                .unwrap();
+            }
+        }
+    }
+
+    async fn record_occurrence(&self, repo: &str, path: &str, count: usize) {
+        match self {
+            SqlStore::Sqlite(pool) => {
+                let conn = sqlite_connection(pool).await;
+                let repo = repo.to_string();
+                let path = path.to_string();
+                let count = count as i64;
+                execute_with_busy_retry(&conn, move |conn| {
+                    conn.execute(
+                        "INSERT INTO occurrences (repo, path, count) VALUES (?1, ?2, ?3)
+                         ON CONFLICT(repo, path) DO UPDATE SET count = excluded.count",
+                        rusqlite::params![repo, path, count],
+                    )
+                })
+                .await;
+            }
+            SqlStore::Postgres(pool) => {
+                let conn = pool.get().await.unwrap();
+                conn.execute(
+                    "INSERT INTO occurrences (repo, path, count) VALUES ($1, $2, $3)
+                     ON CONFLICT (repo, path) DO UPDATE SET count = excluded.count",
+                    &[&repo, &path, &(count as i64)],
+                )
+                .await
+                .unwrap();
+            }
+        }
+    }
+}
+
+/// Builds the [`Store`] named by a `--store` CLI value: `file:PATH`,
+/// `sqlite:PATH` or `postgres:CONNINFO`. A bare `file` is not accepted here
+/// and panics like any other unrecognized value.
+pub async fn store_from_spec(spec: &str) -> Box<dyn Store> {
+    if let Some(path) = spec.strip_prefix("sqlite:") {
+        Box::new(SqlStore::sqlite(path).await)
+    } else if let Some(config) = spec.strip_prefix("postgres:") {
+        Box::new(SqlStore::postgres(config).await)
+    } else if let Some(path) = spec.strip_prefix("file:") {
+        Box::new(FileStore::new(path))
+    } else {
+        panic!(
+            "unrecognized --store value {:?}, expected file:PATH, sqlite:PATH or postgres:CONNINFO",
+            spec
+        )
+    }
+}